@@ -27,6 +27,27 @@ pub trait PyClass:
     /// The closest native ancestor. This is `PyAny` by default, and when you declare
     /// `#[pyclass(extends=PyDict)]`, it's `PyDict`.
     type BaseNativeType: PyTypeInfo + PyNativeType;
+
+    /// Returns the variable-length trailing data owned by this instance, sized as
+    /// `ob_size * PyClassImpl::ITEMSIZE` bytes. Empty for classes that don't declare
+    /// `#[pyclass(var_size)]`.
+    ///
+    /// The returned slice borrows `py`'s lifetime rather than `'static`, so it can't
+    /// outlive the GIL-holding scope the instance was accessed through.
+    ///
+    /// # Safety
+    /// `slf` must point at a valid, fully-initialized `PyCell<Self>` instance that is
+    /// still alive for all of `'py`. This does not itself prevent aliasing: callers must
+    /// not call this more than once at a time for the same instance, the same way they
+    /// must already serialize access to the rest of the instance through `PyRefMut`.
+    unsafe fn var_data<'py>(slf: *mut ffi::PyObject, _py: Python<'py>) -> &'py mut [u8] {
+        if Self::ITEMSIZE == 0 {
+            return &mut [];
+        }
+        let nitems = ffi::Py_SIZE(slf) as usize;
+        let base = (slf as *mut u8).add(std::mem::size_of::<Self::Layout>());
+        std::slice::from_raw_parts_mut(base, nitems * Self::ITEMSIZE)
+    }
 }
 
 fn into_raw<T>(vec: Vec<T>) -> *mut c_void {
@@ -37,6 +58,16 @@ pub(crate) fn create_type_object<T>(py: Python) -> *mut ffi::PyTypeObject
 where
     T: PyClass,
 {
+    let vectorcall = T::get_vectorcall();
+    // When a vectorcall trampoline is provided, `tp_new` must also populate it into the
+    // instance at `__vectorcalloffset__` — CPython's vectorcall dispatch reads that slot
+    // per-instance, so the pointer can't just live on the type.
+    let tp_new = if vectorcall.is_some() {
+        Some(new_with_vectorcall::<T> as ffi::newfunc)
+    } else {
+        T::get_new()
+    };
+
     match unsafe {
         create_type_object_impl(
             py,
@@ -45,16 +76,21 @@ where
             T::NAME,
             T::BaseType::type_object_raw(py),
             std::mem::size_of::<T::Layout>(),
-            T::get_new(),
+            T::ITEMSIZE,
+            tp_new,
             tp_dealloc::<T>,
             T::get_alloc(),
             T::get_free(),
             T::dict_offset(),
             T::weaklist_offset(),
+            vectorcall,
+            T::vectorcall_offset(),
             &T::for_each_method_def,
             &T::for_each_proto_slot,
             T::IS_GC,
             T::IS_BASETYPE,
+            T::IS_IMMUTABLE_TYPE,
+            T::metaclass(py),
         )
     } {
         Ok(type_object) => type_object,
@@ -62,6 +98,26 @@ where
     }
 }
 
+/// `tp_new` wrapper installed whenever a class provides a vectorcall trampoline: it
+/// delegates to the class' real constructor and then writes the trampoline into the
+/// instance at the offset advertised via `__vectorcalloffset__`. CPython requires that
+/// slot to be populated per-instance before `PyVectorcall_Call`/vectorcall dispatch can
+/// use it; it is never initialized by the type object itself.
+unsafe extern "C" fn new_with_vectorcall<T: PyClassImpl>(
+    subtype: *mut ffi::PyTypeObject,
+    args: *mut ffi::PyObject,
+    kwds: *mut ffi::PyObject,
+) -> *mut ffi::PyObject {
+    let obj = (T::get_new().unwrap_or(fallback_new))(subtype, args, kwds);
+    if !obj.is_null() {
+        if let (Some(vectorcall), Some(offset)) = (T::get_vectorcall(), T::vectorcall_offset()) {
+            let slot = (obj as *mut u8).offset(offset as isize) as *mut ffi::vectorcallfunc;
+            slot.write(vectorcall);
+        }
+    }
+    obj
+}
+
 #[allow(clippy::too_many_arguments)]
 unsafe fn create_type_object_impl(
     py: Python,
@@ -70,23 +126,44 @@ unsafe fn create_type_object_impl(
     name: &str,
     base_type_object: *mut ffi::PyTypeObject,
     basicsize: usize,
+    itemsize: usize,
     tp_new: Option<ffi::newfunc>,
     tp_dealloc: ffi::destructor,
     tp_alloc: Option<ffi::allocfunc>,
     tp_free: Option<ffi::freefunc>,
     dict_offset: Option<ffi::Py_ssize_t>,
     weaklist_offset: Option<ffi::Py_ssize_t>,
+    vectorcall: Option<ffi::vectorcallfunc>,
+    vectorcall_offset: Option<ffi::Py_ssize_t>,
     for_each_method_def: &dyn Fn(&mut dyn FnMut(&[PyMethodDefType])),
     for_each_proto_slot: &dyn Fn(&mut dyn FnMut(&[ffi::PyType_Slot])),
     is_gc: bool,
     is_basetype: bool,
+    is_immutable_type: bool,
+    metaclass: Option<*mut ffi::PyTypeObject>,
 ) -> PyResult<*mut ffi::PyTypeObject> {
+    // Var-size classes rely on the default `tp_alloc`/`tp_free` pair (`PyType_GenericAlloc`
+    // and friends), which size and zero the trailing `ob_size * itemsize` region and free it
+    // as a single block using only the instance pointer. A custom allocator has no way to
+    // learn `itemsize` from us, so it would need to duplicate that accounting itself; until
+    // there's a use case for that, require the default pair whenever `itemsize` is non-zero.
+    assert!(
+        itemsize == 0 || (tp_alloc.is_none() && tp_free.is_none()),
+        "var-size pyclasses must use the default tp_alloc/tp_free so the ob_size * itemsize \
+         trailing allocation is sized and freed correctly"
+    );
+
     let mut slots = Vec::new();
 
     fn push_slot(slots: &mut Vec<ffi::PyType_Slot>, slot: c_int, pfunc: *mut c_void) {
         slots.push(ffi::PyType_Slot { slot, pfunc });
     }
 
+    // On Python 3.11+ CPython can manage the instance dict itself (no `__dictoffset__`
+    // slot needed in the struct layout), which is both smaller and faster than the
+    // offset-based scheme we must still use on older interpreters.
+    let use_managed_dict = dict_offset.is_some() && cfg!(Py_3_11);
+
     push_slot(&mut slots, ffi::Py_tp_base, base_type_object as _);
     if let Some(doc) = py_class_doc(tp_doc) {
         push_slot(&mut slots, ffi::Py_tp_doc, doc as _);
@@ -108,12 +185,20 @@ unsafe fn create_type_object_impl(
 
     #[cfg(Py_3_9)]
     {
-        let members = py_class_members(dict_offset, weaklist_offset);
+        let dict_offset = if use_managed_dict { None } else { dict_offset };
+        let members = py_class_members(dict_offset, weaklist_offset, vectorcall_offset);
         if !members.is_empty() {
             push_slot(&mut slots, ffi::Py_tp_members, into_raw(members))
         }
     }
 
+    // vectorcall support: when the class provides a vectorcall trampoline, CPython still
+    // requires a tp_call fallback (e.g. for `type.__call__` or code going through the
+    // slow path), so install the generic `PyVectorcall_Call` helper alongside it.
+    if vectorcall.is_some() {
+        push_slot(&mut slots, ffi::Py_tp_call, ffi::PyVectorcall_Call as _);
+    }
+
     // normal methods
     let methods = py_class_method_defs(for_each_method_def);
     if !methods.is_empty() {
@@ -155,12 +240,31 @@ unsafe fn create_type_object_impl(
     let mut spec = ffi::PyType_Spec {
         name: py_class_qualified_name(module_name, name)?,
         basicsize: basicsize as c_int,
-        itemsize: 0,
-        flags: py_class_flags(has_gc_methods, is_gc, is_basetype),
+        itemsize: itemsize as c_int,
+        flags: py_class_flags(
+            has_gc_methods,
+            is_gc,
+            is_basetype,
+            vectorcall.is_some(),
+            use_managed_dict,
+            is_immutable_type,
+        ),
         slots: slots.as_mut_ptr(),
     };
 
+    // `PyType_FromMetaclass` is the only spec-based constructor that accepts a custom
+    // metaclass, but it only landed in 3.12; on older interpreters we build the type
+    // normally and patch `ob_type` afterwards in `tp_init_additional`.
+    #[cfg(Py_3_12)]
+    let type_object = match metaclass {
+        Some(metaclass) => {
+            ffi::PyType_FromMetaclass(metaclass, ptr::null_mut(), &mut spec, ptr::null_mut())
+        }
+        None => ffi::PyType_FromSpec(&mut spec),
+    };
+    #[cfg(not(Py_3_12))]
     let type_object = ffi::PyType_FromSpec(&mut spec);
+
     if type_object.is_null() {
         Err(PyErr::fetch(py))
     } else {
@@ -173,6 +277,8 @@ unsafe fn create_type_object_impl(
             dict_offset,
             #[cfg(not(Py_3_9))]
             weaklist_offset,
+            #[cfg(not(Py_3_12))]
+            metaclass,
         );
         Ok(type_object as _)
     }
@@ -192,11 +298,22 @@ unsafe fn tp_init_additional(
     #[cfg(not(Py_3_9))] buffer_procs: &ffi::PyBufferProcs,
     #[cfg(not(Py_3_9))] dict_offset: Option<ffi::Py_ssize_t>,
     #[cfg(not(Py_3_9))] weaklist_offset: Option<ffi::Py_ssize_t>,
+    metaclass: Option<*mut ffi::PyTypeObject>,
 ) {
     // Just patch the type objects for the things there's no
     // PyType_FromSpec API for... there's no reason this should work,
     // except for that it does and we have tests.
 
+    // `PyType_FromMetaclass` only exists from 3.12, so on every version covered by this
+    // function we have to swap in a custom metaclass ourselves after the fact.
+    if let Some(metaclass) = metaclass {
+        let obj = type_object as *mut ffi::PyObject;
+        let old_type = (*obj).ob_type;
+        ffi::Py_INCREF(metaclass as *mut ffi::PyObject);
+        (*obj).ob_type = metaclass;
+        ffi::Py_DECREF(old_type as *mut ffi::PyObject);
+    }
+
     // Running this causes PyPy to segfault.
     #[cfg(all(not(PyPy), not(Py_3_10)))]
     {
@@ -236,7 +353,21 @@ fn tp_init_additional(
     #[cfg(all(not(Py_3_9), not(Py_LIMITED_API)))] _buffer_procs: &ffi::PyBufferProcs,
     #[cfg(not(Py_3_9))] _dict_offset: Option<ffi::Py_ssize_t>,
     #[cfg(not(Py_3_9))] _weaklist_offset: Option<ffi::Py_ssize_t>,
+    #[cfg(not(Py_3_12))] _metaclass: Option<*mut ffi::PyTypeObject>,
 ) {
+    // On 3.12+ `PyType_FromMetaclass` already installed the metaclass; on 3.10/3.11, and
+    // under the limited API where the struct fields above are unavailable, fall back to
+    // the stable `Py_SET_TYPE` accessor instead of poking `ob_type` directly.
+    #[cfg(not(Py_3_12))]
+    if let Some(metaclass) = _metaclass {
+        unsafe {
+            let obj = _type_object as *mut ffi::PyObject;
+            let old_type = ffi::Py_TYPE(obj);
+            ffi::Py_INCREF(metaclass as *mut ffi::PyObject);
+            ffi::Py_SET_TYPE(obj, metaclass);
+            ffi::Py_DECREF(old_type as *mut ffi::PyObject);
+        }
+    }
 }
 
 fn py_class_doc(class_doc: &str) -> Option<*mut c_char> {
@@ -266,8 +397,18 @@ fn py_class_qualified_name(module_name: Option<&str>, class_name: &str) -> PyRes
     .into_raw())
 }
 
-fn py_class_flags(has_gc_methods: bool, is_gc: bool, is_basetype: bool) -> c_uint {
-    let mut flags = if has_gc_methods || is_gc {
+fn py_class_flags(
+    has_gc_methods: bool,
+    is_gc: bool,
+    is_basetype: bool,
+    has_vectorcall: bool,
+    use_managed_dict: bool,
+    is_immutable_type: bool,
+) -> c_uint {
+    // A managed dict writes into the GC pre-header that `PyType_GenericAlloc` only
+    // reserves for `Py_TPFLAGS_HAVE_GC` types, so types opting into `MANAGED_DICT` must
+    // also carry `HAVE_GC`, even if they don't otherwise need GC support themselves.
+    let mut flags = if has_gc_methods || is_gc || use_managed_dict {
         ffi::Py_TPFLAGS_DEFAULT | ffi::Py_TPFLAGS_HAVE_GC
     } else {
         ffi::Py_TPFLAGS_DEFAULT
@@ -275,6 +416,21 @@ fn py_class_flags(has_gc_methods: bool, is_gc: bool, is_basetype: bool) -> c_uin
     if is_basetype {
         flags |= ffi::Py_TPFLAGS_BASETYPE;
     }
+    if has_vectorcall {
+        flags |= ffi::Py_TPFLAGS_HAVE_VECTORCALL;
+    }
+    if use_managed_dict {
+        flags |= ffi::Py_TPFLAGS_MANAGED_DICT;
+    }
+
+    // `Py_TPFLAGS_IMMUTABLETYPE` only exists on Python 3.10+; on older interpreters the
+    // type object's attributes stay mutable from Python, same as today.
+    #[cfg(Py_3_10)]
+    if is_immutable_type {
+        flags |= ffi::Py_TPFLAGS_IMMUTABLETYPE;
+    }
+    #[cfg(not(Py_3_10))]
+    let _ = is_immutable_type;
 
     // `c_ulong` and `c_uint` have the same size
     // on some platforms (like windows)
@@ -304,14 +460,15 @@ fn py_class_method_defs(
     defs
 }
 
-/// Generates the __dictoffset__ and __weaklistoffset__ members, to set tp_dictoffset and
-/// tp_weaklistoffset.
+/// Generates the __dictoffset__, __weaklistoffset__ and __vectorcalloffset__ members, to set
+/// tp_dictoffset, tp_weaklistoffset and tp_vectorcall_offset respectively.
 ///
 /// Only works on Python 3.9 and up.
 #[cfg(Py_3_9)]
 fn py_class_members(
     dict_offset: Option<isize>,
     weaklist_offset: Option<isize>,
+    vectorcall_offset: Option<isize>,
 ) -> Vec<ffi::structmember::PyMemberDef> {
     #[inline(always)]
     fn offset_def(name: &'static str, offset: ffi::Py_ssize_t) -> ffi::structmember::PyMemberDef {
@@ -336,6 +493,11 @@ fn py_class_members(
         members.push(offset_def("__weaklistoffset__\0", weaklist_offset));
     }
 
+    // vectorcall support
+    if let Some(vectorcall_offset) = vectorcall_offset {
+        members.push(offset_def("__vectorcalloffset__\0", vectorcall_offset));
+    }
+
     if !members.is_empty() {
         // Safety: Python expects a zeroed entry to mark the end of the defs
         members.push(unsafe { std::mem::zeroed() });